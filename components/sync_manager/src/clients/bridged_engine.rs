@@ -0,0 +1,136 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use sync15::bso::{IncomingBso, OutgoingBso};
+use sync15::bridged_engine::{ApplyResults, BridgedEngine};
+use sync_guid::Guid as SyncGuid;
+
+use super::engine::Engine;
+use super::Command;
+use crate::error::Result;
+
+/// Adapts [`Engine`] to the [`BridgedEngine`] trait, so that Desktop Firefox
+/// can drive the clients engine through the Rust<->JS sync bridge the same
+/// way it drives every other engine, instead of calling `Engine::sync`
+/// directly.
+///
+/// The clients collection is never disabled and always does a full fetch, so
+/// `last_sync`/`sync_id` are meaningless here and are no-ops. The interesting
+/// part is splitting `Engine`'s combined fetch/apply/upload into the
+/// discrete steps the bridge calls one at a time, which means the incoming
+/// records have to be stashed between `store_incoming` and `apply`.
+pub struct BridgedEngineImpl<'a> {
+    engine: Engine<'a>,
+    incoming: RefCell<Vec<IncomingBso>>,
+    /// Whether `apply` queued our own client record in the last batch of
+    /// outgoing records. `set_uploaded` uses this to know whether it should
+    /// commit `last_client_upload`.
+    queued_self_upload: RefCell<bool>,
+    /// Commands `apply` appended to each target's record in the last batch,
+    /// keyed by target client ID. `set_uploaded` uses this to know which
+    /// targets' commands were actually confirmed sent.
+    pending_command_uploads: RefCell<HashMap<SyncGuid, HashSet<Command>>>,
+}
+
+impl<'a> BridgedEngineImpl<'a> {
+    pub fn new(engine: Engine<'a>) -> Self {
+        BridgedEngineImpl {
+            engine,
+            incoming: RefCell::new(Vec::new()),
+            queued_self_upload: RefCell::new(false),
+            pending_command_uploads: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl<'a> BridgedEngine for BridgedEngineImpl<'a> {
+    type Error = crate::error::Error;
+
+    fn last_sync(&self) -> Result<i64> {
+        // The clients collection always does a full fetch, so there's
+        // nothing to track here.
+        Ok(0)
+    }
+
+    fn set_last_sync(&self, _last_sync: i64) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_id(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn reset_sync_id(&self) -> Result<String> {
+        Ok(String::new())
+    }
+
+    fn ensure_current_sync_id(&self, sync_id: &str) -> Result<String> {
+        Ok(sync_id.into())
+    }
+
+    fn sync_started(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn store_incoming(&self, incoming: Vec<IncomingBso>) -> Result<()> {
+        *self.incoming.borrow_mut() = incoming;
+        Ok(())
+    }
+
+    fn apply(&self) -> Result<ApplyResults> {
+        let incoming = self.incoming.borrow_mut().split_off(0);
+        let payloads = incoming
+            .into_iter()
+            .map(IncomingBso::into_payload)
+            .collect::<Result<Vec<_>>>()?;
+        let (outgoing, client_data, queued_self_upload, pending_command_uploads) =
+            self.engine.apply_incoming_payloads(payloads)?;
+
+        // Unlike `last_client_upload`, `ClientData` only reflects what we
+        // read from other devices' records, so it's safe to persist it
+        // regardless of whether our own record's upload below succeeds.
+        self.engine.commit_client_data(&client_data)?;
+        *self.queued_self_upload.borrow_mut() = queued_self_upload;
+        *self.pending_command_uploads.borrow_mut() = pending_command_uploads;
+
+        let records = outgoing
+            .into_iter()
+            .map(OutgoingBso::from_payload)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ApplyResults {
+            records,
+            num_reconciled: None,
+        })
+    }
+
+    fn set_uploaded(&self, _server_modified_millis: i64, ids: &[SyncGuid]) -> Result<()> {
+        // This is the only hook that tells us the server actually accepted
+        // our records, so it's the right (and only) place to commit the
+        // refresh timestamp for our own record.
+        let queued_self_upload = self.queued_self_upload.replace(false);
+        if queued_self_upload && ids.contains(self.engine.client_id()) {
+            self.engine.commit_last_client_upload_now()?;
+        }
+
+        let pending_command_uploads = self.pending_command_uploads.replace(HashMap::new());
+        self.engine.commit_sent_commands(&pending_command_uploads, ids)?;
+
+        Ok(())
+    }
+
+    fn sync_finished(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn wipe(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -2,22 +2,41 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use sql_support::SqlInterruptScope;
 use sync15::{
     CollState, CollectionKeys, CollectionRequest, CollectionUpdate, GlobalState, IncomingChangeset,
     KeyBundle, OutgoingChangeset, Payload, Sync15StorageClient,
 };
+use sync_guid::Guid as SyncGuid;
 
 use super::{
     record::{Client, ClientCommand},
     ser::shrink_to_fit,
-    Settings,
+    ClientData, Command, RemoteClient, Settings, Type,
 };
 use crate::error::{ErrorKind, Result};
 use crate::manager::SyncManager;
 
+/// How long the server should keep an uploaded client record around before
+/// expiring it, in seconds. A device that goes offline for longer than this
+/// simply falls out of the collection instead of lingering forever.
+const CLIENTS_TTL: u32 = 15_552_000; // 180 days.
+
+/// How long we'll go without reuploading our own unchanged client record, in
+/// seconds. We still upload immediately if the record changed or we have
+/// commands to send.
+const CLIENTS_TTL_REFRESH: u64 = 604_800; // 7 days.
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 pub struct Engine<'a> {
     manager: &'a SyncManager,
     interruptee: &'a SqlInterruptScope,
@@ -47,7 +66,11 @@ impl<'a> Engine<'a> {
     /// For these reasons, we implement a specialized `sync` method instead of
     /// implementing `sync15::Store`, even though our methods have similar
     /// signatures.
-    pub fn sync(&self) -> Result<()> {
+    ///
+    /// Returns the `ClientData` collected from every other device's record,
+    /// so that consumers like the tabs engine can resolve an `fxa_device_id`
+    /// to a device name and type without re-fetching the clients collection.
+    pub fn sync(&self) -> Result<ClientData> {
         log::info!("Syncing collection clients");
 
         let coll_keys = CollectionKeys::from_encrypted_bso(
@@ -67,7 +90,8 @@ impl<'a> Engine<'a> {
 
         let inbound = self.fetch_incoming(&mut coll_state)?;
 
-        let outgoing = self.apply_incoming(inbound)?;
+        let (outgoing, client_data, queued_self_upload, pending_command_uploads) =
+            self.apply_incoming(inbound)?;
         coll_state.last_modified = outgoing.timestamp;
 
         self.interruptee.err_if_interrupted()?;
@@ -85,8 +109,27 @@ impl<'a> Engine<'a> {
             upload_info.failed_ids.len()
         );
 
+        // Only record the refresh if our own record was actually part of
+        // this upload *and* the server accepted it. A failed upload must
+        // stay stale so we retry on the next sync, instead of silently
+        // waiting out `CLIENTS_TTL_REFRESH`.
+        if queued_self_upload && !upload_info.failed_ids.contains(&self.settings.client_id) {
+            self.manager.set_last_client_upload(now_secs())?;
+        }
+
+        // Likewise, only treat a target's commands as delivered-and-waiting
+        // once we know the record we appended them to actually made it to
+        // the server; a failed upload must leave them queued for retry.
+        for (target, commands) in &pending_command_uploads {
+            if !upload_info.failed_ids.contains(target) {
+                self.manager.commit_sent_commands(target, commands)?;
+            }
+        }
+
+        self.manager.set_client_data(&client_data)?;
+
         log::info!("Finished syncing clients");
-        Ok(())
+        Ok(client_data)
     }
 
     fn current_client_record(&self) -> Client {
@@ -140,14 +183,60 @@ impl<'a> Engine<'a> {
         self.max_record_payload_size().min(512 * 1024)
     }
 
-    fn apply_incoming(&self, inbound: IncomingChangeset) -> Result<OutgoingChangeset> {
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+    ) -> Result<(
+        OutgoingChangeset,
+        ClientData,
+        bool,
+        HashMap<SyncGuid, HashSet<Command>>,
+    )> {
         let mut outgoing = OutgoingChangeset::new("clients".into(), inbound.timestamp);
         outgoing.timestamp = inbound.timestamp;
 
+        let incoming_payloads = inbound.changes.into_iter().map(|(payload, _)| payload).collect();
+        let (outgoing_payloads, client_data, queued_self_upload, pending_command_uploads) =
+            self.apply_incoming_payloads(incoming_payloads)?;
+        outgoing.changes = outgoing_payloads;
+
+        Ok((
+            outgoing,
+            client_data,
+            queued_self_upload,
+            pending_command_uploads,
+        ))
+    }
+
+    /// The guts of `apply_incoming`, pulled out so the bridged adapter can
+    /// drive it from `store_incoming`/`apply` without needing a full
+    /// `IncomingChangeset` (which carries a per-collection timestamp that
+    /// only the legacy `sync()` path cares about).
+    ///
+    /// The returned `bool` is whether our own client record was queued in
+    /// the outgoing changes this round. The returned map is, per target
+    /// client ID, the commands newly appended to their outgoing record this
+    /// round. Callers must only record the refresh timestamp
+    /// (`SyncManager::set_last_client_upload`) or confirm sent commands
+    /// (`SyncManager::commit_sent_commands`) once the corresponding upload
+    /// has actually succeeded.
+    pub(super) fn apply_incoming_payloads(
+        &self,
+        incoming: Vec<Payload>,
+    ) -> Result<(
+        Vec<Payload>,
+        ClientData,
+        bool,
+        HashMap<SyncGuid, HashSet<Command>>,
+    )> {
+        let mut outgoing = Vec::new();
+        let mut client_data = ClientData::new();
+        let mut queued_self_upload = false;
+        let mut pending_command_uploads = HashMap::new();
+
         self.interruptee.err_if_interrupted()?;
-        let outgoing_commands = self.manager.fetch_outgoing_commands()?;
 
-        for (payload, _) in inbound.changes {
+        for payload in incoming {
             self.interruptee.err_if_interrupted()?;
 
             // Unpack the client record. We should never have tombstones in the
@@ -157,6 +246,7 @@ impl<'a> Engine<'a> {
 
             if client.id == self.settings.client_id {
                 let mut current_client_record = self.current_client_record();
+                let had_incoming_commands = !client.commands.is_empty();
                 for c in client.commands {
                     // If we see our own client record, apply any incoming
                     // commands, remove them from the list, and reupload the
@@ -188,29 +278,112 @@ impl<'a> Engine<'a> {
                     self.memcache_max_record_payload_size(),
                 )?;
 
-                // We always upload our own client record on each sync, even if it
-                // doesn't change, to keep it fresh.
-                outgoing
-                    .changes
-                    .push(Payload::from_record(current_client_record)?);
+                // Only reupload our own record when it actually changed, we
+                // have commands to deliver, or our last upload is stale
+                // enough that it's worth refreshing the server TTL.
+                let record_changed = current_client_record.name != client.name
+                    || current_client_record.typ != client.typ
+                    || !current_client_record.commands.is_empty();
+                let is_stale = self
+                    .settings
+                    .last_client_upload
+                    .map_or(true, |last| now_secs().saturating_sub(last) > CLIENTS_TTL_REFRESH);
+
+                // A peer may have asked us (via `RepairRequest`) to reupload
+                // our own client record. Honor that even if it's otherwise
+                // unchanged and fresh.
+                let repair_requested = self
+                    .manager
+                    .fetch_pending_reuploads("clients")?
+                    .contains(&self.settings.client_id);
+
+                if had_incoming_commands || record_changed || is_stale || repair_requested {
+                    outgoing.push(Payload::from_record(current_client_record)?.with_ttl(CLIENTS_TTL));
+                    queued_self_upload = true;
+                    if repair_requested {
+                        self.manager
+                            .clear_pending_reupload("clients", &self.settings.client_id)?;
+                    }
+                }
             } else {
+                if let Some(fxa_device_id) = client.fxa_device_id.clone() {
+                    client_data.insert(
+                        fxa_device_id.clone(),
+                        RemoteClient {
+                            remote_client_id: client.id.clone(),
+                            fxa_device_id,
+                            device_name: client.name.clone(),
+                            device_type: client
+                                .typ
+                                .as_deref()
+                                .map_or(Type::Unknown, |typ| typ.parse().unwrap()),
+                        },
+                    );
+                }
+
                 let commands = client
                     .commands
                     .iter()
                     .filter_map(ClientCommand::as_command)
                     .collect::<HashSet<_>>();
-                let new_commands = outgoing_commands.difference(&commands);
-                client
-                    .commands
-                    .extend(new_commands.into_iter().map(|&command| command.into()));
+
+                // A command we'd queued for this client that's no longer in
+                // its record was applied and cleared by the peer; stop
+                // resending it instead of queuing it again forever.
+                self.manager
+                    .mark_delivered_commands(&client.id, &commands)?;
+
+                let outgoing_commands = self.manager.fetch_outgoing_commands(&client.id)?;
+                let new_commands: HashSet<Command> =
+                    outgoing_commands.difference(&commands).cloned().collect();
+                if !new_commands.is_empty() {
+                    client
+                        .commands
+                        .extend(new_commands.iter().cloned().map(ClientCommand::from));
+                    pending_command_uploads.insert(client.id.clone(), new_commands);
+                }
                 shrink_to_fit(
                     &mut client.commands,
                     self.memcache_max_record_payload_size(),
                 )?;
-                outgoing.changes.push(Payload::from_record(client)?);
+                outgoing.push(Payload::from_record(client)?.with_ttl(CLIENTS_TTL));
             }
         }
 
-        Ok(outgoing)
+        Ok((outgoing, client_data, queued_self_upload, pending_command_uploads))
+    }
+
+    pub(super) fn client_id(&self) -> &sync_guid::Guid {
+        &self.settings.client_id
+    }
+
+    /// Stamps `last_client_upload` with the current time. Only call this
+    /// once the server has confirmed our own record was actually accepted.
+    pub(super) fn commit_last_client_upload_now(&self) -> Result<()> {
+        self.manager.set_last_client_upload(now_secs())
+    }
+
+    /// Persists `ClientData` so other engines can join against it. Safe to
+    /// call regardless of whether our own record's upload succeeds, since
+    /// it only reflects what we read from other devices' records.
+    pub(super) fn commit_client_data(&self, client_data: &ClientData) -> Result<()> {
+        self.manager.set_client_data(client_data)
+    }
+
+    /// Confirms, for each target in `pending`, that its queued commands were
+    /// actually uploaded, provided the server accepted that target's record
+    /// (i.e. its ID is in `ids`). Only call this once the corresponding
+    /// upload has been confirmed successful.
+    pub(super) fn commit_sent_commands(
+        &self,
+        pending: &HashMap<SyncGuid, HashSet<Command>>,
+        ids: &[SyncGuid],
+    ) -> Result<()> {
+        for (target, commands) in pending {
+            if ids.contains(target) {
+                self.manager.commit_sent_commands(target, commands)?;
+            }
+        }
+        Ok(())
     }
 }
@@ -2,14 +2,35 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::collections::HashMap;
+use std::str::FromStr;
+
 use sync_guid::Guid as SyncGuid;
 
+mod bridged_engine;
 mod engine;
 mod record;
 mod ser;
 
+pub use bridged_engine::BridgedEngineImpl;
 pub use engine::Engine;
 
+/// The clients collection, keyed by each remote client's FxA device ID.
+/// Other engines (e.g. tabs) join against this to resolve a device ID to a
+/// human-readable name and type without re-fetching the clients collection
+/// themselves.
+pub type ClientData = HashMap<String, RemoteClient>;
+
+/// A read-only view of another device's client record, collected while
+/// syncing the clients collection.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RemoteClient {
+    pub remote_client_id: SyncGuid,
+    pub fxa_device_id: String,
+    pub device_name: String,
+    pub device_type: Type,
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Settings {
     /// The ID for this client. This should be stable across syncs, and is
@@ -22,6 +43,10 @@ pub struct Settings {
     pub client_type: Type,
     /// The device ID of this client, linking it to the FxA device manager.
     pub fxa_device_id: String,
+    /// The last time (in epoch seconds) we uploaded our own client record,
+    /// or `None` if we've never uploaded it. Used to decide whether our
+    /// record is stale enough to refresh; see `CLIENTS_TTL_REFRESH`.
+    pub last_client_upload: Option<u64>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -29,6 +54,12 @@ pub enum Type {
     Desktop,
     Mobile,
     Tablet,
+    VR,
+    TV,
+    /// A type we don't recognize, or that's absent from the record. Kept
+    /// instead of discarded so consumers can still show "an unknown device"
+    /// rather than silently dropping it.
+    Unknown,
 }
 
 impl Type {
@@ -37,11 +68,31 @@ impl Type {
             Type::Desktop => "desktop",
             Type::Mobile => "mobile",
             Type::Tablet => "tablet",
+            Type::VR => "vr",
+            Type::TV => "tv",
+            Type::Unknown => "unknown",
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+impl FromStr for Type {
+    type Err = std::convert::Infallible;
+
+    /// Parses a client record's raw `type` string. Unrecognized or absent
+    /// values become `Type::Unknown` rather than being lost.
+    fn from_str(typ: &str) -> Result<Self, Self::Err> {
+        Ok(match typ {
+            "desktop" => Type::Desktop,
+            "mobile" => Type::Mobile,
+            "tablet" => Type::Tablet,
+            "vr" => Type::VR,
+            "tv" => Type::TV,
+            _ => Type::Unknown,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Command {
     WipeLogins,
     WipeHistory,
@@ -51,4 +102,93 @@ pub enum Command {
     ResetHistory,
     ResetBookmarks,
     ResetAll,
+    /// Sent by a device that's missing records in a collection (e.g. a
+    /// bookmark whose parent GUID isn't on the server), asking whoever
+    /// holds them to re-upload the listed IDs. `requester` is the record ID
+    /// of the device that should receive the `RepairResponse`.
+    RepairRequest {
+        collection: String,
+        ids: Vec<SyncGuid>,
+        requester: SyncGuid,
+        flow_id: String,
+    },
+    /// Sent in reply to a `RepairRequest`, carrying the same `flow_id` so
+    /// telemetry on both ends can be correlated.
+    RepairResponse {
+        collection: String,
+        ids: Vec<SyncGuid>,
+        flow_id: String,
+    },
+}
+
+// `flow_id` is telemetry, not part of a command's identity: two repair
+// commands that only differ by flow ID are the same piece of work, and
+// should dedupe/diff as such. So we hand-roll equality and hashing instead
+// of deriving them, and leave `flow_id` out.
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        use Command::*;
+        match (self, other) {
+            (WipeLogins, WipeLogins)
+            | (WipeHistory, WipeHistory)
+            | (WipeBookmarks, WipeBookmarks)
+            | (WipeAll, WipeAll)
+            | (ResetLogins, ResetLogins)
+            | (ResetHistory, ResetHistory)
+            | (ResetBookmarks, ResetBookmarks)
+            | (ResetAll, ResetAll) => true,
+            (
+                RepairRequest {
+                    collection: c1,
+                    ids: i1,
+                    requester: r1,
+                    ..
+                },
+                RepairRequest {
+                    collection: c2,
+                    ids: i2,
+                    requester: r2,
+                    ..
+                },
+            ) => c1 == c2 && i1 == i2 && r1 == r2,
+            (
+                RepairResponse {
+                    collection: c1,
+                    ids: i1,
+                    ..
+                },
+                RepairResponse {
+                    collection: c2,
+                    ids: i2,
+                    ..
+                },
+            ) => c1 == c2 && i1 == i2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Command {}
+
+impl std::hash::Hash for Command {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Command::RepairRequest {
+                collection,
+                ids,
+                requester,
+                ..
+            } => {
+                collection.hash(state);
+                ids.hash(state);
+                requester.hash(state);
+            }
+            Command::RepairResponse { collection, ids, .. } => {
+                collection.hash(state);
+                ids.hash(state);
+            }
+            _ => {}
+        }
+    }
 }
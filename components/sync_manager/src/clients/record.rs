@@ -91,30 +91,105 @@ impl ClientCommand {
                 _ => None,
             },
             "resetAll" => Some(Command::ResetAll),
+            "repairRequest" => self.as_repair_request(),
+            "repairResponse" => self.as_repair_response(),
             _ => None,
         }
     }
 
+    /// `repairRequest` args are `[collection, requester, id...]`: the
+    /// collection to repair, the record ID of the device that should get
+    /// the `RepairResponse`, then the requested IDs.
+    fn as_repair_request(&self) -> Option<Command> {
+        let collection = self.args.get(0)?.clone();
+        let requester = SyncGuid::from(self.args.get(1)?.as_str());
+        let ids = self.args[2..]
+            .iter()
+            .map(|id| SyncGuid::from(id.as_str()))
+            .collect();
+        let flow_id = self.flow_id.clone().unwrap_or_default();
+        Some(Command::RepairRequest {
+            collection,
+            ids,
+            requester,
+            flow_id,
+        })
+    }
+
+    /// `repairResponse` args are `[collection, id...]`: it's already
+    /// addressed to the requester by virtue of being queued on their
+    /// record, so it doesn't need to repeat a requester ID.
+    fn as_repair_response(&self) -> Option<Command> {
+        let collection = self.args.get(0)?.clone();
+        let ids = self.args[1..]
+            .iter()
+            .map(|id| SyncGuid::from(id.as_str()))
+            .collect();
+        let flow_id = self.flow_id.clone().unwrap_or_default();
+        Some(Command::RepairResponse {
+            collection,
+            ids,
+            flow_id,
+        })
+    }
+
     #[inline]
     pub fn from_command_with_flow_id(command: Command, flow_id: String) -> ClientCommand {
         ClientCommand::from_command(command, Some(flow_id))
     }
 
     fn from_command(command: Command, flow_id: Option<String>) -> ClientCommand {
-        let (name, args): (&str, &[&str]) = match command {
-            Command::WipeLogins => ("wipeEngine", &["passwords"]),
-            Command::WipeHistory => ("wipeEngine", &["history"]),
-            Command::WipeBookmarks => ("wipeEngine", &["bookmarks"]),
-            Command::WipeAll => ("wipeAll", &[]),
-            Command::ResetLogins => ("resetEngine", &["passwords"]),
-            Command::ResetHistory => ("resetEngine", &["history"]),
-            Command::ResetBookmarks => ("resetEngine", &["bookmarks"]),
-            Command::ResetAll => ("resetAll", &[]),
-        };
-        ClientCommand {
-            name: name.into(),
-            args: args.iter().map(|&n| n.into()).collect(),
-            flow_id,
+        match command {
+            Command::RepairRequest {
+                collection,
+                ids,
+                requester,
+                flow_id: repair_flow_id,
+            } => {
+                let mut args = Vec::with_capacity(2 + ids.len());
+                args.push(collection);
+                args.push(requester.to_string());
+                args.extend(ids.into_iter().map(|id| id.to_string()));
+                ClientCommand {
+                    name: "repairRequest".into(),
+                    args,
+                    flow_id: Some(repair_flow_id),
+                }
+            }
+            Command::RepairResponse {
+                collection,
+                ids,
+                flow_id: repair_flow_id,
+            } => {
+                let mut args = Vec::with_capacity(1 + ids.len());
+                args.push(collection);
+                args.extend(ids.into_iter().map(|id| id.to_string()));
+                ClientCommand {
+                    name: "repairResponse".into(),
+                    args,
+                    flow_id: Some(repair_flow_id),
+                }
+            }
+            simple => {
+                let (name, args): (&str, &[&str]) = match simple {
+                    Command::WipeLogins => ("wipeEngine", &["passwords"]),
+                    Command::WipeHistory => ("wipeEngine", &["history"]),
+                    Command::WipeBookmarks => ("wipeEngine", &["bookmarks"]),
+                    Command::WipeAll => ("wipeAll", &[]),
+                    Command::ResetLogins => ("resetEngine", &["passwords"]),
+                    Command::ResetHistory => ("resetEngine", &["history"]),
+                    Command::ResetBookmarks => ("resetEngine", &["bookmarks"]),
+                    Command::ResetAll => ("resetAll", &[]),
+                    Command::RepairRequest { .. } | Command::RepairResponse { .. } => {
+                        unreachable!()
+                    }
+                };
+                ClientCommand {
+                    name: name.into(),
+                    args: args.iter().map(|&n| n.into()).collect(),
+                    flow_id,
+                }
+            }
         }
     }
 }
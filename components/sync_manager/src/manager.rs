@@ -0,0 +1,243 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use sync_guid::Guid as SyncGuid;
+
+use crate::clients::{ClientData, Command};
+use crate::error::Result;
+
+/// Coordinates state that's shared across the engines driven through this
+/// crate, and that doesn't belong to any single collection: the clients
+/// collection's view of other devices (`ClientData`), the refresh timestamp
+/// for our own client record, and the per-target command queues used to
+/// send and receive client commands like wipes and repairs.
+#[derive(Default)]
+pub struct SyncManager {
+    client_data: RefCell<ClientData>,
+    last_client_upload: RefCell<Option<u64>>,
+    outgoing_commands: RefCell<HashMap<SyncGuid, HashSet<Command>>>,
+    /// Commands from `outgoing_commands` that we've actually included in a
+    /// *confirmed* outgoing upload to `target`'s record, via
+    /// `commit_sent_commands`. Only commands in here are eligible to be
+    /// pruned by `mark_delivered_commands` — a command can't have been
+    /// applied and cleared by the peer if we never confirmed sending it.
+    sent_commands: RefCell<HashMap<SyncGuid, HashSet<Command>>>,
+    /// Record IDs, keyed by collection, that a `RepairRequest` asked us to
+    /// reupload. The engine that owns each collection drains this on its
+    /// next sync; we don't have a handle to other engines here.
+    pending_reuploads: RefCell<HashMap<String, HashSet<SyncGuid>>>,
+}
+
+impl SyncManager {
+    pub fn new() -> Self {
+        SyncManager::default()
+    }
+
+    pub(crate) fn set_client_data(&self, client_data: &ClientData) -> Result<()> {
+        *self.client_data.borrow_mut() = client_data.clone();
+        Ok(())
+    }
+
+    pub(crate) fn set_last_client_upload(&self, when: u64) -> Result<()> {
+        *self.last_client_upload.borrow_mut() = Some(when);
+        Ok(())
+    }
+
+    /// Commands we still want to send to `target`, queued by
+    /// `handle_repair_request` (or a future caller) and not yet acked by
+    /// `mark_delivered_commands`.
+    pub(crate) fn fetch_outgoing_commands(&self, target: &SyncGuid) -> Result<HashSet<Command>> {
+        Ok(self
+            .outgoing_commands
+            .borrow()
+            .get(target)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Drops any of `target`'s *confirmed-sent* commands that aren't in
+    /// `still_pending`: the peer's record no longer lists them, so it must
+    /// have applied them. A command we've only queued but never confirmed
+    /// uploading can't possibly be in `still_pending` yet (we haven't put it
+    /// on the record), so it's left alone rather than treated as delivered.
+    pub(crate) fn mark_delivered_commands(
+        &self,
+        target: &SyncGuid,
+        still_pending: &HashSet<Command>,
+    ) -> Result<()> {
+        let mut sent_commands = self.sent_commands.borrow_mut();
+        let sent = match sent_commands.get_mut(target) {
+            Some(sent) => sent,
+            None => return Ok(()),
+        };
+        let delivered: Vec<Command> = sent
+            .iter()
+            .filter(|command| !still_pending.contains(command))
+            .cloned()
+            .collect();
+        if delivered.is_empty() {
+            return Ok(());
+        }
+        for command in &delivered {
+            sent.remove(command);
+        }
+        if let Some(queued) = self.outgoing_commands.borrow_mut().get_mut(target) {
+            for command in &delivered {
+                queued.remove(command);
+            }
+        }
+        Ok(())
+    }
+
+    fn queue_outgoing_command(&self, target: SyncGuid, command: Command) -> Result<()> {
+        self.outgoing_commands
+            .borrow_mut()
+            .entry(target)
+            .or_default()
+            .insert(command);
+        Ok(())
+    }
+
+    /// Confirms that `commands` were actually included in a successful
+    /// outgoing upload to `target`'s record, making them eligible for
+    /// `mark_delivered_commands` to prune once the peer applies and clears
+    /// them. Must only be called after the corresponding upload succeeded.
+    pub(crate) fn commit_sent_commands(
+        &self,
+        target: &SyncGuid,
+        commands: &HashSet<Command>,
+    ) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        self.sent_commands
+            .borrow_mut()
+            .entry(target.clone())
+            .or_default()
+            .extend(commands.iter().cloned());
+        Ok(())
+    }
+
+    /// The record IDs other engines have told us about for `collection`. We
+    /// only track the clients collection's own records here; other
+    /// collections' engines aren't wired up to the sync manager yet.
+    fn known_record_ids(&self, collection: &str) -> HashSet<SyncGuid> {
+        if collection != "clients" {
+            return HashSet::new();
+        }
+        self.client_data
+            .borrow()
+            .values()
+            .map(|client| client.remote_client_id.clone())
+            .collect()
+    }
+
+    /// Record IDs in `collection` that a `RepairRequest` asked us to
+    /// reupload, and that we actually recognized. The engine that owns
+    /// `collection` should include these in its next outgoing batch, then
+    /// call `clear_pending_reupload` for each one it actually queues.
+    ///
+    /// Of the collections this crate knows about, only `clients` has an
+    /// engine wired up to call this (see `Engine::apply_incoming_payloads`);
+    /// other collections' engines live outside this crate and aren't wired
+    /// up yet, so repair requests against them are acked but never acted on.
+    pub(crate) fn fetch_pending_reuploads(&self, collection: &str) -> Result<HashSet<SyncGuid>> {
+        Ok(self
+            .pending_reuploads
+            .borrow()
+            .get(collection)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Marks `id` in `collection` as no longer needing a repair reupload,
+    /// once the owning engine has actually queued it for upload.
+    pub(crate) fn clear_pending_reupload(&self, collection: &str, id: &SyncGuid) -> Result<()> {
+        if let Some(ids) = self.pending_reuploads.borrow_mut().get_mut(collection) {
+            ids.remove(id);
+        }
+        Ok(())
+    }
+
+    /// Applies a command sent to our own client record.
+    pub(crate) fn apply_incoming_command(&self, command: Command) -> Result<()> {
+        match command {
+            Command::WipeLogins
+            | Command::WipeHistory
+            | Command::WipeBookmarks
+            | Command::WipeAll
+            | Command::ResetLogins
+            | Command::ResetHistory
+            | Command::ResetBookmarks
+            | Command::ResetAll => {
+                // TODO(lina): Dispatch these to the engine(s) they target
+                // once the sync manager owns engine dispatch.
+                Ok(())
+            }
+            Command::RepairRequest {
+                collection,
+                ids,
+                requester,
+                flow_id,
+            } => self.handle_repair_request(collection, ids, requester, flow_id),
+            Command::RepairResponse {
+                collection,
+                ids,
+                flow_id,
+            } => {
+                log::info!(
+                    "Got repair response for collection {} ({} record(s), flow ID {})",
+                    collection,
+                    ids.len(),
+                    flow_id
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles a `RepairRequest` for records in `collection`: queues the IDs
+    /// we actually recognize for reupload, and sends `requester` a
+    /// correlated `RepairResponse` (with the same `flow_id`) naming only
+    /// those IDs, so it knows not to keep waiting on the rest.
+    fn handle_repair_request(
+        &self,
+        collection: String,
+        ids: Vec<SyncGuid>,
+        requester: SyncGuid,
+        flow_id: String,
+    ) -> Result<()> {
+        let known_ids = self.known_record_ids(&collection);
+        let (to_reupload, unknown): (Vec<SyncGuid>, Vec<SyncGuid>) =
+            ids.into_iter().partition(|id| known_ids.contains(id));
+
+        if !unknown.is_empty() {
+            log::warn!(
+                "Ignoring {} unknown record ID(s) in repair request for collection {}",
+                unknown.len(),
+                collection
+            );
+        }
+
+        self.pending_reuploads
+            .borrow_mut()
+            .entry(collection.clone())
+            .or_default()
+            .extend(to_reupload.iter().cloned());
+
+        // If every requested ID was unknown, `to_reupload` is empty, and this
+        // is exactly the ack the requester needs: nothing to wait on.
+        self.queue_outgoing_command(
+            requester,
+            Command::RepairResponse {
+                collection,
+                ids: to_reupload,
+                flow_id,
+            },
+        )
+    }
+}